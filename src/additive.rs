@@ -0,0 +1,75 @@
+use crate::adsr::{ADSRParams, Curve};
+
+// one sine in an additive oscillator, see Oscillator::get_additive
+#[derive(Clone)]
+pub struct Partial {
+    pub ratio: f32, // frequency relative to the fundamental
+    pub amplitude: f32,
+    pub adsr: Option<ADSRParams>, // None to hold at amplitude forever
+}
+
+impl Partial {
+    pub fn new(ratio: f32, amplitude: f32) -> Self {
+        Self {
+            ratio,
+            amplitude,
+            adsr: None,
+        }
+    }
+
+    pub fn with_adsr(self, adsr: ADSRParams) -> Self {
+        Self {
+            adsr: Some(adsr),
+            ..self
+        }
+    }
+
+    // amplitudes[0] is the fundamental, amplitudes[1] the 2nd harmonic, etc
+    pub fn harmonics(amplitudes: &[f32]) -> Vec<Self> {
+        amplitudes
+            .iter()
+            .enumerate()
+            .map(|(i, &amplitude)| Self::new((i + 1) as f32, amplitude))
+            .collect()
+    }
+
+    // hammond style drawbars, each 0 to 8, in the order on the organ:
+    // 16', 5 1/3', 8', 4', 2 2/3', 2', 1 3/5', 1 1/3', 1'
+    pub fn drawbars(drawbars: [u8; 9]) -> Vec<Self> {
+        const RATIOS: [f32; 9] = [0.5, 1.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 8.0];
+
+        RATIOS
+            .iter()
+            .zip(drawbars.iter())
+            .map(|(&ratio, &level)| Self::new(ratio, level.min(8) as f32 / 8.0 / 9.0))
+            .collect()
+    }
+
+    // jean-claude risset's bell, the higher partials die out faster
+    pub fn bell(length: f32) -> Vec<Self> {
+        const PARTIALS: [(f32, f32, f32); 9] = [
+            // (ratio, amplitude, relative length)
+            (0.56, 1.0, 1.0),
+            (0.92, 1.0, 0.65),
+            (1.19, 2.67, 0.325),
+            (1.7, 1.67, 0.35),
+            (2.0, 1.46, 0.25),
+            (2.74, 1.33, 0.2),
+            (3.0, 1.33, 0.15),
+            (3.76, 1.0, 0.1),
+            (4.07, 1.33, 0.075),
+        ];
+
+        PARTIALS
+            .iter()
+            .map(|&(ratio, amplitude, relative)| {
+                Self::new(ratio, amplitude / 13.0).with_adsr(ADSRParams {
+                    sustain_percent: 1.0,
+                    release_length: length * relative,
+                    release_curve: Curve::Exponential,
+                    ..ADSRParams::zero()
+                })
+            })
+            .collect()
+    }
+}
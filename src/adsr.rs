@@ -1,14 +1,56 @@
 use crate::util::{lerp, BITRATE_F};
 
+// shape of a segment between two levels
+// exponential rises slowly then quickly, and falls quickly then slowly (eg a natural release)
+// logarithmic is the opposite
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Curve {
+    Linear,
+    Exponential,
+    Logarithmic,
+    Custom(f32), // curvature, > 0 bends like exponential, < 0 like logarithmic, 0 is linear
+}
+
+impl Curve {
+    pub fn curvature(self) -> f32 {
+        match self {
+            Self::Linear => 0.0,
+            Self::Exponential => 5.0,
+            Self::Logarithmic => -5.0,
+            // much past this and exp_m1 overflows in apply()
+            Self::Custom(k) => k.clamp(-30.0, 30.0),
+        }
+    }
+
+    // like lerp, (0 < x < 1) to (a < ans < b) but bent
+    pub fn apply(self, x: f32, a: f32, b: f32) -> f32 {
+        let k = self.curvature();
+
+        let x = if k.abs() < 1e-3 {
+            x
+        } else if b >= a {
+            (k * x).exp_m1() / k.exp_m1()
+        } else {
+            1.0 - (k * (1.0 - x)).exp_m1() / k.exp_m1()
+        };
+
+        lerp(x, a, b)
+    }
+}
+
 // all units seconds except percent
 #[derive(Clone, PartialEq)]
 pub struct ADSRParams {
     pub attack_length: f32,
     pub decay_length: f32,
     pub sustain_percent: f32,
-    pub sustain_length: f32,
+    pub sustain_length: f32, // ignored if gate is set
     pub release_length: f32,
     pub quiet_length: f32,
+    pub gate: bool, // if true, sustain until release() is called instead of for sustain_length
+    pub attack_curve: Curve,
+    pub decay_curve: Curve,
+    pub release_curve: Curve,
 }
 
 impl ADSRParams {
@@ -38,6 +80,10 @@ impl ADSRParams {
             sustain_length: 0.0,
             release_length: 0.0,
             quiet_length: 0.0,
+            gate: false,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
         }
     }
 
@@ -66,6 +112,10 @@ impl Default for ADSRParams {
             sustain_length: 1.25,
             release_length: 1.0,
             quiet_length: 0.5,
+            gate: false,
+            attack_curve: Curve::Linear,
+            decay_curve: Curve::Linear,
+            release_curve: Curve::Linear,
         }
     }
 }
@@ -91,6 +141,9 @@ pub struct ADSR {
     params: ADSRParams,
     state: State,
     progress: u32,
+    level: f32,        // last output
+    start: f32,        // level when the current state started
+    retriggered: bool, // take the params from the next Oscillator::adsr() call, eg a new velocity
 }
 
 impl ADSR {
@@ -109,6 +162,7 @@ impl ADSR {
     fn switch_state(&mut self, state: State) {
         self.state = state;
         self.progress = 0;
+        self.start = self.level;
     }
 
     // if true, the ending of this envelope can be cut short (interrupted)
@@ -120,6 +174,10 @@ impl ADSR {
         }
     }
 
+    pub fn is_attack(&self) -> bool {
+        self.state == State::Attack
+    }
+
     pub fn release(&mut self) {
         // allow to be released multiple times, with subsequent releases ignored
         if !self.is_done() {
@@ -127,6 +185,21 @@ impl ADSR {
         }
     }
 
+    // start the attack again, from the current level so it doesnt click
+    pub fn retrigger(&mut self) {
+        self.switch_state(State::Attack);
+        self.retriggered = true;
+    }
+
+    // the params a retrigger was waiting for, see Oscillator::adsr
+    pub fn take_params(&mut self, params: ADSRParams) {
+        if self.retriggered {
+            self.retriggered = false;
+            params.assert();
+            self.params = params;
+        }
+    }
+
     pub fn next(&mut self) -> Option<f32> {
         let x: f32 = match &self.state {
             State::Attack => {
@@ -136,7 +209,11 @@ impl ADSR {
                     self.switch_state(State::Decay);
                     1.0
                 } else {
-                    self.progress as f32 / duration_f
+                    self.params.attack_curve.apply(
+                        self.progress as f32 / duration_f,
+                        self.start,
+                        1.0,
+                    )
                 }
             }
             State::Decay => {
@@ -146,7 +223,7 @@ impl ADSR {
                     self.switch_state(State::Sustain);
                     self.params.sustain_percent
                 } else {
-                    lerp(
+                    self.params.decay_curve.apply(
                         self.progress as f32 / duration_f,
                         1.0,
                         self.params.sustain_percent,
@@ -156,7 +233,7 @@ impl ADSR {
             State::Sustain => {
                 let duration_f = self.params.sustain_length * BITRATE_F;
 
-                if self.progress >= (duration_f as u32) {
+                if !self.params.gate && self.progress >= (duration_f as u32) {
                     self.switch_state(State::Release);
                 }
 
@@ -169,9 +246,10 @@ impl ADSR {
                     self.switch_state(State::Quiet);
                     0.0
                 } else {
-                    lerp(
+                    // released early, from wherever the attack or decay got to
+                    self.params.release_curve.apply(
                         self.progress as f32 / duration_f,
-                        self.params.sustain_percent,
+                        self.start,
                         0.0,
                     )
                 }
@@ -188,6 +266,7 @@ impl ADSR {
         };
 
         self.progress += 1;
+        self.level = x;
 
         Some(x)
     }
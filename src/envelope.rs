@@ -0,0 +1,214 @@
+use crate::adsr::Curve;
+use crate::util::BITRATE_F;
+
+// ramp from wherever the envelope is to level, over length seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub length: f32,
+    pub level: f32,
+    pub curve: Curve,
+}
+
+impl Segment {
+    pub fn new(length: f32, level: f32) -> Self {
+        Self {
+            length,
+            level,
+            curve: Curve::Linear,
+        }
+    }
+
+    pub fn curved(length: f32, level: f32, curve: Curve) -> Self {
+        Self {
+            length,
+            level,
+            curve,
+        }
+    }
+}
+
+// general breakpoint envelope, for anything that doesnt fit in ADSRParams
+// segment indices below are into segments
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct EnvelopeParams {
+    pub start: f32, // level before the first segment
+    pub segments: Vec<Segment>,
+    pub sustain: Option<usize>, // hold at the end of this segment until released
+    pub looping: Option<(usize, usize)>, // (first, last) segments to repeat until released
+}
+
+impl EnvelopeParams {
+    fn assert(&self) {
+        let len = self.segments.len();
+
+        assert!(self.segments.iter().all(|seg| seg.length >= 0.0));
+        assert!(self.sustain.map(|i| i < len).unwrap_or(true));
+
+        if let Some((first, last)) = self.looping {
+            assert!(first <= last && last < len);
+
+            // or it would loop forever without producing a sample
+            let loop_samples: u32 = self.segments[first..=last]
+                .iter()
+                .map(|seg| (seg.length * BITRATE_F) as u32)
+                .sum();
+            assert!(loop_samples > 0);
+        }
+    }
+
+    pub fn build(self) -> Envelope {
+        self.assert();
+
+        Envelope {
+            level: self.start,
+            start: self.start,
+            params: self,
+            ..Default::default()
+        }
+    }
+
+    // delay, attack, hold, decay, sustain, release
+    // all units seconds except sustain_percent
+    pub fn dahdsr(
+        delay: f32,
+        attack: f32,
+        hold: f32,
+        decay: f32,
+        sustain_percent: f32,
+        release: f32,
+    ) -> Self {
+        Self {
+            start: 0.0,
+            segments: vec![
+                Segment::new(delay, 0.0),
+                Segment::new(attack, 1.0),
+                Segment::new(hold, 1.0),
+                Segment::new(decay, sustain_percent),
+                Segment::new(release, 0.0),
+            ],
+            sustain: Some(3),
+            looping: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Envelope {
+    params: EnvelopeParams,
+    segment: usize,
+    progress: u32,
+    level: f32, // last output
+    start: f32, // level when the current segment started
+    released: bool,
+    retriggered: bool, // take the params from the next Oscillator::envelope() call
+}
+
+impl Envelope {
+    pub fn copy(&self) -> Self {
+        self.params.clone().build()
+    }
+
+    pub fn reset(&mut self) {
+        *self = self.copy();
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.segment >= self.params.segments.len()
+    }
+
+    // if true, the ending of this envelope can be cut short (interrupted)
+    pub fn is_done(&self) -> bool {
+        self.released || self.is_end()
+    }
+
+    // heading up to a higher level, like an attack
+    pub fn is_rising(&self) -> bool {
+        self.params
+            .segments
+            .get(self.segment)
+            .map(|segment| segment.level > self.start)
+            .unwrap_or(false)
+    }
+
+    fn switch_segment(&mut self, segment: usize) {
+        self.segment = segment;
+        self.progress = 0;
+        self.start = self.level;
+    }
+
+    // skip past the sustain point and the loop
+    // without either, the envelope is one shot and this does nothing
+    pub fn release(&mut self) {
+        if self.released {
+            return;
+        }
+        self.released = true;
+
+        let last_held = self
+            .params
+            .sustain
+            .into_iter()
+            .chain(self.params.looping.map(|(_, last)| last))
+            .max();
+
+        if let Some(last_held) = last_held {
+            if self.segment <= last_held {
+                self.switch_segment(last_held + 1);
+            }
+        }
+    }
+
+    // start from the first segment again, from the current level so it doesnt click
+    pub fn retrigger(&mut self) {
+        self.released = false;
+        self.switch_segment(0);
+        self.retriggered = true;
+    }
+
+    // the params a retrigger was waiting for, see Oscillator::envelope
+    pub fn take_params(&mut self, params: EnvelopeParams) {
+        if self.retriggered {
+            self.retriggered = false;
+            params.assert();
+            self.params = params;
+        }
+    }
+
+    pub fn next(&mut self) -> Option<f32> {
+        loop {
+            let segment = self.params.segments.get(self.segment)?;
+            let duration_f = segment.length * BITRATE_F;
+
+            if self.progress < (duration_f as u32) {
+                let x = segment.curve.apply(
+                    self.progress as f32 / duration_f,
+                    self.start,
+                    segment.level,
+                );
+
+                self.progress += 1;
+                self.level = x;
+
+                break Some(x);
+            }
+
+            // this segment is over
+            self.level = segment.level;
+
+            if !self.released {
+                if self.params.sustain == Some(self.segment) {
+                    break Some(self.level);
+                }
+
+                if let Some((first, last)) = self.params.looping {
+                    if self.segment == last {
+                        self.switch_segment(first);
+                        continue;
+                    }
+                }
+            }
+
+            self.switch_segment(self.segment + 1);
+        }
+    }
+}
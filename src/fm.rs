@@ -0,0 +1,172 @@
+use crate::adsr::ADSRParams;
+use crate::oscillator::Oscillator;
+use crate::synth_template::SynthTrait;
+
+pub const MAX_OPERATORS: usize = 6;
+
+#[derive(Clone)]
+pub struct Operator {
+    pub ratio: f32,    // frequency relative to the note
+    pub index: f32,    // output level for carriers, modulation index (radians) for modulators
+    pub feedback: f32, // self modulation, in radians
+    pub adsr: ADSRParams,
+}
+
+impl Default for Operator {
+    fn default() -> Self {
+        Self {
+            ratio: 1.0,
+            index: 1.0,
+            feedback: 0.0,
+            adsr: Default::default(),
+        }
+    }
+}
+
+// which operators modulate which, dx style
+// operators are numbered from 0, and may only be modulated by higher numbered operators,
+// so evaluating them from last to first always has the modulators ready
+pub struct Algorithm {
+    pub operators: usize,
+    pub edges: &'static [(usize, usize)], // (modulator, target)
+    pub carriers: &'static [usize],
+}
+
+impl Algorithm {
+    fn assert(&self) {
+        assert!(self.operators <= MAX_OPERATORS);
+        assert!(!self.carriers.is_empty());
+        assert!(self.carriers.iter().all(|&c| c < self.operators));
+        assert!(self.edges.iter().all(|&(m, t)| m < self.operators && t < m));
+    }
+}
+
+// the 8 algorithms of the 4 operator yamaha synths (dx21, dx27, tx81z), in order
+// yamaha numbers operators from 1, so their operator n is operator n - 1 here
+pub const ALGORITHMS_4OP: [Algorithm; 8] = [
+    // 3 -> 2 -> 1 -> 0
+    Algorithm {
+        operators: 4,
+        edges: &[(3, 2), (2, 1), (1, 0)],
+        carriers: &[0],
+    },
+    // (2 + 3) -> 1 -> 0
+    Algorithm {
+        operators: 4,
+        edges: &[(3, 1), (2, 1), (1, 0)],
+        carriers: &[0],
+    },
+    // (3 + (2 -> 1)) -> 0
+    Algorithm {
+        operators: 4,
+        edges: &[(3, 0), (2, 1), (1, 0)],
+        carriers: &[0],
+    },
+    // ((3 -> 2) + 1) -> 0
+    Algorithm {
+        operators: 4,
+        edges: &[(3, 2), (2, 0), (1, 0)],
+        carriers: &[0],
+    },
+    // (1 -> 0) + (3 -> 2)
+    Algorithm {
+        operators: 4,
+        edges: &[(1, 0), (3, 2)],
+        carriers: &[0, 2],
+    },
+    // 3 -> (0 + 1 + 2)
+    Algorithm {
+        operators: 4,
+        edges: &[(3, 0), (3, 1), (3, 2)],
+        carriers: &[0, 1, 2],
+    },
+    // 0 + 1 + (3 -> 2)
+    Algorithm {
+        operators: 4,
+        edges: &[(3, 2)],
+        carriers: &[0, 1, 2],
+    },
+    // 0 + 1 + 2 + 3
+    Algorithm {
+        operators: 4,
+        edges: &[],
+        carriers: &[0, 1, 2, 3],
+    },
+];
+
+// a few of the dx7's 32 algorithms, named after their dx7 algorithm number
+pub const DX7_ALGORITHM_1: Algorithm = Algorithm {
+    operators: 6,
+    edges: &[(1, 0), (5, 4), (4, 3), (3, 2)],
+    carriers: &[0, 2],
+};
+
+pub const DX7_ALGORITHM_5: Algorithm = Algorithm {
+    operators: 6,
+    edges: &[(1, 0), (3, 2), (5, 4)],
+    carriers: &[0, 2, 4],
+};
+
+pub const DX7_ALGORITHM_32: Algorithm = Algorithm {
+    operators: 6,
+    edges: &[],
+    carriers: &[0, 1, 2, 3, 4, 5],
+};
+
+#[derive(Clone)]
+pub struct FmVoice {
+    pub freq: f32,
+    pub operators: Vec<Operator>,
+    pub algorithm: &'static Algorithm,
+}
+
+impl FmVoice {
+    pub fn new(freq: f32, operators: Vec<Operator>, algorithm: &'static Algorithm) -> Self {
+        algorithm.assert();
+        assert_eq!(operators.len(), algorithm.operators);
+
+        Self {
+            freq,
+            operators,
+            algorithm,
+        }
+    }
+}
+
+impl SynthTrait for FmVoice {
+    fn next(&mut self, osc: &Oscillator) -> Option<f32> {
+        let mut outputs = [0.0; MAX_OPERATORS];
+        let mut envelopes = [None; MAX_OPERATORS];
+
+        for (i, op) in self.operators.iter().enumerate().rev() {
+            let modulation: f32 = self
+                .algorithm
+                .edges
+                .iter()
+                .filter(|&&(_, target)| target == i)
+                .map(|&(modulator, _)| outputs[modulator])
+                .sum();
+
+            // each operator gets its own sub oscillator so they dont share phase or envelope
+            let (env, out) = osc.sub_osc(i, |osc| {
+                let env = osc.adsr(op.adsr.clone()).next();
+                let out = osc.get_pm(self.freq * op.ratio, modulation, op.feedback);
+                (env, out)
+            });
+
+            envelopes[i] = env;
+            outputs[i] = out * op.index * env.unwrap_or(0.0);
+        }
+
+        let carriers = self.algorithm.carriers;
+
+        // the note is over once every carrier has finished
+        if carriers.iter().all(|&c| envelopes[c].is_none()) {
+            return None;
+        }
+
+        let out: f32 = carriers.iter().map(|&c| outputs[c]).sum();
+
+        Some(out / carriers.len() as f32)
+    }
+}
@@ -4,39 +4,90 @@ use std::sync::mpsc;
 
 use wmidi::Note;
 
+mod additive;
 mod adsr;
 mod audio_util;
+mod envelope;
+mod fm;
 mod manychannel;
 mod midi_io;
+mod midi_synth;
+mod noise;
 mod oscillator;
+mod pluck;
 mod synth_template;
 mod util;
+mod velocity;
+mod wavetable;
 
 use crate::adsr::{ADSRParams, ADSR};
 use crate::audio_util::{play_live, save_to_wav};
 use crate::midi_io::{MidiInput, SimpleMidiMessage};
+use crate::midi_synth::MidiSynth;
 use crate::oscillator::Oscillator;
-use crate::synth_template::{SynthTrait, SynthTraitDefault};
+use crate::synth_template::{Controls, SynthTrait, SynthTraitDefault, VoiceTrait};
 use crate::util::{distort, lerp, scale};
+use crate::velocity::VelocityCurve;
 
 #[derive(Default, Clone)]
-struct Voice(f32);
+struct Voice {
+    freq: f32,
+    velocity: f32,
+    gate: bool, // hold until released, instead of for a fixed time
+    controls: Controls,
+}
+
+impl Voice {
+    const VELOCITY_CURVE: VelocityCurve = VelocityCurve::Soft;
+}
 
 impl SynthTrait for Voice {
     fn next(&mut self, osc: &Oscillator) -> Option<f32> {
+        // harder notes are louder and attack faster, down to the default attack at full velocity
         let vol = osc
             .adsr(ADSRParams {
+                attack_length: Self::VELOCITY_CURVE.scale(self.velocity, 1.0, 0.5),
                 quiet_length: 0.0,
+                gate: self.gate,
                 ..Default::default()
             })
             .next()?;
 
-        let out = osc.get_sin(self.0).signum() * vol;
+        // mod wheel or pressure add up to half a semitone of vibrato
+        let depth = self.controls.mod_wheel.max(self.controls.pressure);
+        let vibrato = osc.get_sin(5.5) * depth * 0.5;
+        let freq = self.freq * self.controls.bend * 2.0f32.powf(vibrato / 12.0);
+
+        // timbre above center narrows the pulse, from square down to 10%
+        let width = 0.5 - (self.controls.timbre - 0.5).max(0.0) * 0.8;
+
+        let vol = vol * Self::VELOCITY_CURVE.apply(self.velocity);
+        let out = osc.get_pulse(freq, width) * vol;
 
         Some(out)
     }
 }
 
+impl VoiceTrait for Voice {
+    fn new(note: Note, velocity: f32) -> Self {
+        Self {
+            freq: note.to_freq_f32(),
+            velocity,
+            gate: true,
+            controls: Default::default(),
+        }
+    }
+
+    fn modulate(&mut self, controls: &Controls) {
+        self.controls = *controls;
+    }
+
+    // keep the first note's velocity, so the level doesnt jump
+    fn set_note(&mut self, note: Note, _velocity: f32) {
+        self.freq = note.to_freq_f32();
+    }
+}
+
 struct Synth<T: Iterator<Item = Note>> {
     voice: Option<Voice>,
     notes: T,
@@ -61,7 +112,13 @@ impl<T: Iterator<Item = Note>> SynthTrait for Synth<T> {
             }
 
             if let Some(note) = self.notes.next() {
-                self.voice.replace(Voice(note.to_freq_f32()));
+                // nothing releases these notes, so they end on their own
+                self.voice.replace(Voice {
+                    freq: note.to_freq_f32(),
+                    velocity: 1.0,
+                    gate: false,
+                    controls: Default::default(),
+                });
                 continue;
             }
 
@@ -70,134 +127,10 @@ impl<T: Iterator<Item = Note>> SynthTrait for Synth<T> {
     }
 }
 
-#[derive(Clone)]
-enum VoiceNode {
-    Free(Option<usize>), // index to next free, or None if last
-    Used { voice: Voice, note: Note },
-}
-
-impl VoiceNode {
-    fn next(&mut self, osc: &Oscillator) -> Option<f32> {
-        match self {
-            Self::Used { ref mut voice, .. } => voice.next(osc),
-            _ => None,
-        }
-    }
-
-    fn free(&self) -> Option<Option<usize>> {
-        match self {
-            Self::Free(free) => Some(*free),
-            _ => None,
-        }
-    }
-
-    fn note(&self) -> Option<Note> {
-        match self {
-            Self::Used { note, .. } => Some(*note),
-            _ => None,
-        }
-    }
-}
-
-impl Default for VoiceNode {
+impl Default for MidiSynth {
+    // every channel plays the default Voice
     fn default() -> Self {
-        Self::Free(None)
-    }
-}
-
-// implementation from http://gameprogrammingpatterns.com/object-pool.html#a-free-list
-struct VoiceArray {
-    voices: [VoiceNode; Self::SIZE],
-    free: Option<usize>, // index to first free
-}
-
-impl VoiceArray {
-    const SIZE: usize = 32;
-
-    fn next(&mut self, osc: &Oscillator) -> Option<f32> {
-        let mut full_sample = 0.0;
-
-        // this wasnt in the original implementation, but testing showed that without
-        // resetting self.free to None each sample, it would create a loop in the
-        // linked list, and it would itself point to or cause some node to point to a used node,
-        // which should never happen
-        self.free = None;
-
-        for (i, voice) in self.voices.iter_mut().enumerate() {
-            match osc.sub_osc(i, |osc| voice.next(osc)) {
-                Some(sample) => full_sample += sample,
-                None => {
-                    *voice = VoiceNode::Free(self.free);
-                    self.free = Some(i);
-                }
-            }
-        }
-
-        full_sample *= 0.3;
-        // assert!(full_sample.abs() <= 1.0, "clipping");
-
-        Some(full_sample)
-    }
-
-    // returns free voice and its index in the array, and marks it as used
-    fn get_free(&mut self) -> (usize, &mut VoiceNode) {
-        let free = self.free.unwrap(); // todo: handle None
-
-        let voice = &mut self.voices[free];
-
-        self.free = voice.free().unwrap();
-
-        (free, voice)
-    }
-}
-
-impl Default for VoiceArray {
-    fn default() -> Self {
-        let mut voices: [VoiceNode; Self::SIZE] = Default::default();
-
-        for (i, voice) in voices.iter_mut().enumerate() {
-            *voice = VoiceNode::Free(Some(i + 1));
-        }
-
-        *voices.last_mut().unwrap() = VoiceNode::Free(None);
-
-        Self {
-            voices,
-            free: Some(0),
-        }
-    }
-}
-
-#[derive(Default)]
-struct MidiSynth {
-    input: MidiInput,
-    voices: VoiceArray,
-}
-
-impl SynthTrait for MidiSynth {
-    fn next(&mut self, osc: &Oscillator) -> Option<f32> {
-        // idk whether to process all messages at once or only one per sample
-        match self.input.try_recv() {
-            Ok(SimpleMidiMessage::NoteOn(note)) => {
-                let voice = Voice(note.to_freq_f32());
-                let (i, node) = self.voices.get_free();
-                *node = VoiceNode::Used { voice, note };
-                osc.sub_osc(i, |osc| osc.reset());
-            }
-            Ok(SimpleMidiMessage::NoteOff(note)) => {
-                // uhh should i use a hashmap, instead of this linear search?
-                self.voices
-                    .voices
-                    .iter_mut()
-                    .enumerate()
-                    .filter(|(_, voice)| voice.note() == Some(note))
-                    .for_each(|(i, _)| osc.sub_osc(i, |osc| osc.release()));
-            }
-            Err(mpsc::TryRecvError::Empty) => {}
-            Err(mpsc::TryRecvError::Disconnected) => unreachable!(), // connection is not closed while MidiSynth is not dropped
-        };
-
-        self.voices.next(osc)
+        Self::new::<Voice>()
     }
 }
 
@@ -3,15 +3,21 @@ use std::ops::{Deref, DerefMut};
 use std::sync::mpsc;
 
 use midir::MidiInputConnection;
-use wmidi::{MidiMessage, Note};
+use wmidi::{Channel, ControlFunction, MidiMessage, Note, U14, U7};
 
+// velocities and control values are scaled to 0 to 1, pitch bend to -1 to 1
 #[derive(Debug, Clone)]
 pub enum SimpleMidiMessage {
-    NoteOn(Note),
-    NoteOff(Note),
+    NoteOn(Note, f32),
+    NoteOff(Note, f32),
+    PitchBend(f32),
+    ChannelPressure(f32),
+    KeyPressure(Note, f32),
+    ControlChange(ControlFunction, f32),
 }
 
-type Receiver = mpsc::Receiver<SimpleMidiMessage>;
+// (microseconds since some point in the past, channel, message)
+type Receiver = mpsc::Receiver<(u64, Channel, SimpleMidiMessage)>;
 
 pub struct MidiInput {
     pub receiver: Receiver,
@@ -37,24 +43,44 @@ impl MidiInput {
 
         let (sender, receiver) = mpsc::channel();
 
-        let process_msg = move |midi: &[u8]| {
+        let process_msg = move |time: u64, midi: &[u8]| {
             let midi = MidiMessage::try_from(midi).unwrap();
             println!("midi: {:?}", midi);
 
-            let msg = match midi {
-                MidiMessage::NoteOn(_chl, note, _vel) => SimpleMidiMessage::NoteOn(note),
-                MidiMessage::NoteOff(_chl, note, _vel) => SimpleMidiMessage::NoteOff(note),
+            let (chl, msg) = match midi {
+                // a note on with 0 velocity is how a lot of keyboards send note off
+                MidiMessage::NoteOn(chl, note, vel) if u8::from(vel) == 0 => {
+                    (chl, SimpleMidiMessage::NoteOff(note, 0.0))
+                }
+                MidiMessage::NoteOn(chl, note, vel) => {
+                    (chl, SimpleMidiMessage::NoteOn(note, u7(vel)))
+                }
+                MidiMessage::NoteOff(chl, note, vel) => {
+                    (chl, SimpleMidiMessage::NoteOff(note, u7(vel)))
+                }
+                MidiMessage::PitchBendChange(chl, bend) => {
+                    (chl, SimpleMidiMessage::PitchBend(u14(bend)))
+                }
+                MidiMessage::PolyphonicKeyPressure(chl, note, pressure) => {
+                    (chl, SimpleMidiMessage::KeyPressure(note, u7(pressure)))
+                }
+                MidiMessage::ChannelPressure(chl, pressure) => {
+                    (chl, SimpleMidiMessage::ChannelPressure(u7(pressure)))
+                }
+                MidiMessage::ControlChange(chl, cc, value) => {
+                    (chl, SimpleMidiMessage::ControlChange(cc, u7(value)))
+                }
                 _ => return,
             };
 
-            sender.send(msg).unwrap();
+            sender.send((time, chl, msg)).unwrap();
         };
 
         let connection = input
             .connect(
                 &port,
                 "synth",
-                move |_time, bytes, _| process_msg(bytes),
+                move |time, bytes, _| process_msg(time, bytes),
                 (),
             )
             .unwrap();
@@ -66,6 +92,16 @@ impl MidiInput {
     }
 }
 
+// 0 to 127 to 0 to 1
+fn u7(x: U7) -> f32 {
+    u8::from(x) as f32 / 127.0
+}
+
+// 0 to 16383, centered on 8192, to -1 to 1
+fn u14(x: U14) -> f32 {
+    ((u16::from(x) as f32 - 8192.0) / 8191.0).max(-1.0)
+}
+
 impl Default for MidiInput {
     fn default() -> Self {
         Self::new(None)
@@ -0,0 +1,1135 @@
+use std::collections::VecDeque;
+use std::sync::mpsc;
+
+use rustc_hash::FxHashMap;
+use wmidi::{Channel, ControlFunction, Note};
+
+use crate::midi_io::{MidiInput, SimpleMidiMessage};
+use crate::oscillator::Oscillator;
+use crate::synth_template::{Controls, SynthTrait, VoiceTrait};
+use crate::util::{lerp, BITRATE, BITRATE_F};
+
+#[derive(Clone)]
+enum VoiceNode<V> {
+    Free(Option<usize>), // index to next free, or None if last
+    Used {
+        voice: V,
+        note: Note,
+        channel: Channel,
+        velocity: f32,
+        pressure: f32,   // polyphonic aftertouch
+        held: bool,      // key is still down
+        sostenuto: bool, // key was down when the sostenuto pedal was pressed
+        released: bool,
+        started: u64, // when the note started, counting notes, for stealing the oldest
+        level: f32,   // recent peak output, for stealing the quietest
+        glide: (f32, f32), // (semitones away from note, semitones per sample back towards it)
+        rendered: bool, // has played a sample, so its envelopes exist
+    },
+}
+
+// which voice to cut off when a note comes in and every voice is in use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VoiceStealing {
+    Oldest,
+    Quietest,
+    SameNote,      // the same note if it is already playing, otherwise the oldest
+    ReleasedFirst, // the oldest released note, otherwise the oldest
+}
+
+// a stolen voice, with a copy of its oscillator so it can fade out while the slot is reused
+struct Fading<V> {
+    voice: V,
+    osc: Oscillator,
+    left: u32, // samples until silent
+}
+
+impl<V: SynthTrait> VoiceNode<V> {
+    fn next(&mut self, osc: &Oscillator) -> Option<f32> {
+        match self {
+            Self::Used {
+                voice,
+                released,
+                rendered,
+                ..
+            } => {
+                let out = voice.next(osc);
+
+                // released before it had anything to release, eg a note off in the same sample
+                if !*rendered {
+                    *rendered = true;
+                    if *released {
+                        osc.release();
+                    }
+                }
+
+                out
+            }
+            _ => None,
+        }
+    }
+
+    // osc is this voice's oscillator
+    // the envelopes are only made on the first sample, so before that this waits until after it
+    fn release(&mut self, osc: &Oscillator) {
+        if let Self::Used {
+            released, rendered, ..
+        } = self
+        {
+            if *rendered {
+                osc.release();
+            }
+            *released = true;
+        }
+    }
+
+    fn free(&self) -> Option<Option<usize>> {
+        match self {
+            Self::Free(free) => Some(*free),
+            _ => None,
+        }
+    }
+
+    fn note(&self) -> Option<Note> {
+        match self {
+            Self::Used { note, .. } => Some(*note),
+            _ => None,
+        }
+    }
+
+    fn voice_mut(&mut self) -> Option<&mut V> {
+        match self {
+            Self::Used { voice, .. } => Some(voice),
+            _ => None,
+        }
+    }
+
+    fn track_level(&mut self, sample: f32) {
+        if let Self::Used { level, .. } = self {
+            // decays to about 10% in 50ms
+            *level = sample.abs().max(*level * 0.999);
+        }
+    }
+}
+
+impl<V> Default for VoiceNode<V> {
+    fn default() -> Self {
+        Self::Free(None)
+    }
+}
+
+const DEFAULT_POLYPHONY: usize = 32;
+
+// implementation from http://gameprogrammingpatterns.com/object-pool.html#a-free-list
+struct VoiceArray<V> {
+    voices: Vec<VoiceNode<V>>,
+    free: Option<usize>, // index to first free
+    fading: Vec<Fading<V>>,
+    active: usize, // used voices, so an idle array can be skipped
+}
+
+impl<V: SynthTrait> VoiceArray<V> {
+    // long enough to not click, short enough to not be heard
+    const FADE_LENGTH: f32 = 0.005;
+
+    fn new(polyphony: usize) -> Self {
+        assert!(polyphony > 0);
+
+        let mut voices: Vec<_> = (1..=polyphony).map(|i| VoiceNode::Free(Some(i))).collect();
+        *voices.last_mut().unwrap() = VoiceNode::Free(None);
+
+        Self {
+            voices,
+            free: Some(0),
+            fading: Vec::new(),
+            active: 0,
+        }
+    }
+
+    // nothing is playing or fading out
+    fn is_idle(&self) -> bool {
+        self.active == 0 && self.fading.is_empty()
+    }
+
+    fn next(&mut self, osc: &Oscillator) -> Option<f32> {
+        let mut full_sample = 0.0;
+
+        // this wasnt in the original implementation, but testing showed that without
+        // resetting self.free to None each sample, it would create a loop in the
+        // linked list, and it would itself point to or cause some node to point to a used node,
+        // which should never happen
+        self.free = None;
+
+        for (i, voice) in self.voices.iter_mut().enumerate() {
+            // free slots have nothing to play, dont look up their oscillators
+            if voice.free().is_some() {
+                *voice = VoiceNode::Free(self.free);
+                self.free = Some(i);
+                continue;
+            }
+
+            match osc.sub_osc(i, |osc| voice.next(osc)) {
+                Some(sample) => {
+                    voice.track_level(sample);
+                    full_sample += sample;
+                }
+                None => {
+                    *voice = VoiceNode::Free(self.free);
+                    self.free = Some(i);
+                    self.active -= 1;
+                }
+            }
+        }
+
+        let fade_samples = Self::FADE_LENGTH * BITRATE_F;
+        self.fading.retain(|fading| fading.left > 0);
+        for fading in self.fading.iter_mut() {
+            match fading.voice.next(&fading.osc) {
+                Some(sample) => full_sample += sample * fading.left as f32 / fade_samples,
+                None => fading.left = 0,
+            }
+            fading.left = fading.left.saturating_sub(1);
+        }
+
+        // assert!(full_sample.abs() <= 1.0, "clipping");
+
+        Some(full_sample)
+    }
+
+    // returns free voice and its index in the array, and marks it as used
+    // if there are none free, steals one, fading it out in the background
+    fn get_free(
+        &mut self,
+        osc: &Oscillator,
+        stealing: VoiceStealing,
+        note: Note,
+    ) -> (usize, &mut VoiceNode<V>) {
+        let free = match self.free {
+            Some(free) => free,
+            None => self.steal(osc, stealing, note),
+        };
+
+        let voice = &mut self.voices[free];
+
+        self.free = voice.free().unwrap();
+        self.active += 1;
+
+        (free, voice)
+    }
+
+    // frees a used voice, and returns its index
+    fn steal(&mut self, osc: &Oscillator, stealing: VoiceStealing, note: Note) -> usize {
+        let collect_used = |rendered_only: bool| -> Vec<_> {
+            self.voices
+                .iter()
+                .enumerate()
+                .filter_map(|(i, node)| match node {
+                    VoiceNode::Used {
+                        note,
+                        released,
+                        started,
+                        level,
+                        rendered,
+                        ..
+                    } if *rendered || !rendered_only => {
+                        Some((i, *note, *released, *started, *level))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // voices that havent played yet havent been heard, eg the rest of a chord in this sample
+        // unless every voice started this sample
+        let mut used = collect_used(true);
+        if used.is_empty() {
+            used = collect_used(false);
+        }
+
+        let oldest = |(_, _, _, started, _): &&(usize, Note, bool, u64, f32)| *started;
+
+        let victim = match stealing {
+            VoiceStealing::Oldest => used.iter().min_by_key(oldest),
+            // a voice in its attack is quiet because it just started, not because its fading away
+            VoiceStealing::Quietest => used
+                .iter()
+                .filter(|v| !osc.sub_osc(v.0, |osc| osc.is_attacking()))
+                .min_by(|a, b| a.4.total_cmp(&b.4).then(a.3.cmp(&b.3)))
+                .or_else(|| used.iter().min_by_key(oldest)),
+            VoiceStealing::SameNote => used
+                .iter()
+                .filter(|v| v.1 == note)
+                .min_by_key(oldest)
+                .or_else(|| used.iter().min_by_key(oldest)),
+            VoiceStealing::ReleasedFirst => used
+                .iter()
+                .filter(|v| v.2)
+                .min_by_key(oldest)
+                .or_else(|| used.iter().min_by_key(oldest)),
+        };
+
+        let i = victim.expect("no voices to steal").0;
+
+        let node = std::mem::replace(&mut self.voices[i], VoiceNode::Free(None));
+        self.active -= 1;
+        if let VoiceNode::Used { voice, .. } = node {
+            self.fading.push(Fading {
+                voice,
+                osc: osc.sub_osc(i, |osc| osc.clone()),
+                left: (Self::FADE_LENGTH * BITRATE_F) as u32,
+            });
+        }
+
+        i
+    }
+}
+
+impl<V: SynthTrait> Default for VoiceArray<V> {
+    fn default() -> Self {
+        Self::new(DEFAULT_POLYPHONY)
+    }
+}
+
+// mpe zone, a master channel for messages to the whole zone,
+// then member channels that each carry one note and its expression
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MpeZone {
+    master: Channel,
+    members: u8,
+}
+
+impl MpeZone {
+    // master is channel 1, members start at channel 2
+    pub fn lower(members: u8) -> Self {
+        assert!(members <= 15);
+
+        Self {
+            master: Channel::Ch1,
+            members,
+        }
+    }
+
+    // master is channel 16, members start at channel 15 and go down
+    pub fn upper(members: u8) -> Self {
+        assert!(members <= 15);
+
+        Self {
+            master: Channel::Ch16,
+            members,
+        }
+    }
+
+    fn is_member(&self, channel: Channel) -> bool {
+        let (master, channel) = (self.master.index(), channel.index());
+
+        if master == 0 {
+            1 <= channel && channel <= self.members
+        } else {
+            master - self.members <= channel && channel < master
+        }
+    }
+
+    fn channels(self) -> impl Iterator<Item = Channel> {
+        (0..16)
+            .map(|i| Channel::from_index(i).unwrap())
+            .filter(move |&c| c == self.master || self.is_member(c))
+    }
+}
+
+// midi note number, as a float so pitches can be between notes
+fn semitones(note: Note) -> f32 {
+    u8::from(note) as f32
+}
+
+// portamento, new notes slide from the last note's pitch, linearly in semitones
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Glide {
+    Time(f32), // seconds to reach the note, however far away it is
+    Rate(f32), // seconds per octave
+}
+
+impl Glide {
+    // (offset, step) for VoiceNode::Used::glide, from a note offset semitones away
+    fn start(self, offset: f32) -> (f32, f32) {
+        let samples = match self {
+            Self::Time(time) => time * BITRATE_F,
+            Self::Rate(rate) => rate * BITRATE_F * offset.abs() / 12.0,
+        };
+
+        (offset, offset.abs() / samples.max(1.0))
+    }
+
+    fn next((offset, step): (f32, f32)) -> (f32, f32) {
+        (offset.signum() * (offset.abs() - step).max(0.0), step)
+    }
+}
+
+// what a note on does when its key is already down, eg a drum roll without note offs in between
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RepeatedNote {
+    Retrigger, // restart the voice that is playing it
+    Stack,     // start another voice, note offs then release them oldest first
+}
+
+// which of the held keys a mono part plays
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NotePriority {
+    Last,
+    Lowest,
+    Highest,
+}
+
+// play one note at a time, see Part::mono
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mono {
+    pub priority: NotePriority,
+    pub legato: bool, // if true, overlapping notes change pitch without restarting the envelopes
+}
+
+impl Mono {
+    // held is in the order the keys went down
+    fn pick(self, held: &[(Note, Channel, f32)]) -> Option<(Note, Channel, f32)> {
+        match self.priority {
+            NotePriority::Last => held.last().copied(),
+            NotePriority::Lowest => held.iter().copied().min_by_key(|&(note, _, _)| note),
+            NotePriority::Highest => held.iter().copied().max_by_key(|&(note, _, _)| note),
+        }
+    }
+}
+
+// per note expression, from an mpe member channel
+#[derive(Debug, Clone, Copy)]
+struct Expression {
+    pitch_bend: f32, // -1 to 1
+    pressure: f32,
+    timbre: f32,
+}
+
+impl Default for Expression {
+    fn default() -> Self {
+        Self {
+            pitch_bend: 0.0,
+            pressure: 0.0,
+            timbre: 0.5,
+        }
+    }
+}
+
+// one midi channel's voices and controllers, see MidiSynth
+// or a whole mpe zone's, if mpe is set
+pub struct Part<V> {
+    voices: VoiceArray<V>,
+    pub bend_range: f32, // semitones at full pitch bend
+    pitch_bend: f32,     // -1 to 1
+    mod_wheel: f32,
+    pressure: f32, // channel aftertouch
+    timbre: f32,
+    volume: f32,
+    expression: f32,
+    sustain: bool,   // damper pedal is down
+    sostenuto: bool, // sostenuto pedal is down
+    pub stealing: VoiceStealing,
+    notes_started: u64,
+    pub gain_compensation: bool, // if true, turn down as more voices play, so chords dont clip
+    compensation: f32,           // smoothed, so it doesnt jump as notes start and end
+    pub repeated: RepeatedNote,
+    // held voices for each key, oldest first, as (index, started)
+    keys: FxHashMap<(Channel, Note), Vec<(usize, u64)>>,
+    pub glide: Option<Glide>,
+    last_note: Option<f32>, // pitch of the last note played, in semitones, to glide from
+    pub mono: Option<Mono>, // None for polyphonic
+    held_notes: Vec<(Note, Channel, f32)>, // (note, channel, velocity), for mono
+    mpe: Option<MpeZone>,
+    pub mpe_bend_range: f32, // semitones at full pitch bend on member channels
+    mpe_channels: [Expression; 16], // indexed by channel
+}
+
+impl<V: VoiceTrait> Part<V> {
+    // the pool is on the heap, so this can be large
+    pub fn with_polyphony(mut self, polyphony: usize) -> Self {
+        self.voices = VoiceArray::new(polyphony);
+        self.keys.clear();
+        self
+    }
+
+    fn is_mpe_member(&self, channel: Channel) -> bool {
+        self.mpe
+            .map(|zone| zone.is_member(channel))
+            .unwrap_or(false)
+    }
+
+    // controls for a voice playing on channel
+    fn controls(&self, channel: Channel) -> Controls {
+        let mut bend = self.pitch_bend * self.bend_range;
+        let mut pressure = self.pressure;
+        let mut timbre = self.timbre;
+
+        if self.is_mpe_member(channel) {
+            let expression = self.mpe_channels[channel.index() as usize];
+            bend += expression.pitch_bend * self.mpe_bend_range;
+            pressure = pressure.max(expression.pressure);
+            timbre = expression.timbre;
+        }
+
+        Controls {
+            bend: 2.0f32.powf(bend / 12.0),
+            mod_wheel: self.mod_wheel,
+            pressure,
+            timbre,
+        }
+    }
+
+    fn pitch_bend(&mut self, channel: Channel, bend: f32) {
+        if self.is_mpe_member(channel) {
+            self.mpe_channels[channel.index() as usize].pitch_bend = bend;
+        } else {
+            self.pitch_bend = bend;
+        }
+    }
+
+    fn channel_pressure(&mut self, channel: Channel, pressure: f32) {
+        if self.is_mpe_member(channel) {
+            self.mpe_channels[channel.index() as usize].pressure = pressure;
+        } else {
+            self.pressure = pressure;
+        }
+    }
+
+    fn key_pressure(&mut self, channel: Channel, note: Note, pressure: f32) {
+        for node in self.voices.voices.iter_mut() {
+            if let VoiceNode::Used {
+                note: n,
+                channel: c,
+                pressure: p,
+                ..
+            } = node
+            {
+                if *n == note && *c == channel {
+                    *p = pressure;
+                }
+            }
+        }
+    }
+
+    fn control_change(
+        &mut self,
+        osc: &Oscillator,
+        channel: Channel,
+        cc: ControlFunction,
+        value: f32,
+    ) {
+        match cc {
+            // mpe uses cc74 for the slide / timbre dimension
+            ControlFunction::SOUND_CONTROLLER_5 if self.is_mpe_member(channel) => {
+                self.mpe_channels[channel.index() as usize].timbre = value;
+            }
+            ControlFunction::SOUND_CONTROLLER_5 => self.timbre = value,
+            ControlFunction::MODULATION_WHEEL => self.mod_wheel = value,
+            ControlFunction::CHANNEL_VOLUME => self.volume = value,
+            ControlFunction::EXPRESSION_CONTROLLER => self.expression = value,
+            ControlFunction::DAMPER_PEDAL => {
+                self.sustain = value >= 0.5;
+                self.release_unheld(osc);
+            }
+            ControlFunction::SOSTENUTO => {
+                let down = value >= 0.5;
+
+                // only catch the notes that are down right as the pedal goes down
+                if down && !self.sostenuto {
+                    for node in self.voices.voices.iter_mut() {
+                        if let VoiceNode::Used {
+                            held, sostenuto, ..
+                        } = node
+                        {
+                            *sostenuto = *held;
+                        }
+                    }
+                }
+
+                if !down {
+                    for node in self.voices.voices.iter_mut() {
+                        if let VoiceNode::Used { sostenuto, .. } = node {
+                            *sostenuto = false;
+                        }
+                    }
+                }
+
+                self.sostenuto = down;
+                self.release_unheld(osc);
+            }
+            // switching mode also means all notes off
+            ControlFunction::MONO_OPERATION => {
+                self.all_notes_off(osc);
+                self.mono.get_or_insert(Mono {
+                    priority: NotePriority::Last,
+                    legato: true,
+                });
+            }
+            ControlFunction::POLY_OPERATION => {
+                self.all_notes_off(osc);
+                self.mono = None;
+            }
+            _ => {}
+        }
+    }
+
+    // as if every key went up, pedals still hold their notes
+    fn all_notes_off(&mut self, osc: &Oscillator) {
+        self.held_notes.clear();
+        self.keys.clear();
+
+        for node in self.voices.voices.iter_mut() {
+            if let VoiceNode::Used { held, .. } = node {
+                *held = false;
+            }
+        }
+
+        self.release_unheld(osc);
+    }
+
+    fn note_on(&mut self, osc: &Oscillator, channel: Channel, note: Note, velocity: f32) {
+        if let Some(mono) = self.mono {
+            self.held_notes
+                .retain(|&(n, c, _)| (n, c) != (note, channel));
+            self.held_notes.push((note, channel, velocity));
+
+            // eg a higher note under low note priority doesnt change anything
+            if mono.pick(&self.held_notes) == Some((note, channel, velocity)) {
+                let legato = mono.legato && self.held_notes.len() > 1;
+                self.play_mono(osc, channel, note, velocity, legato);
+            }
+            return;
+        }
+
+        let retrigger = match self.repeated {
+            RepeatedNote::Retrigger => self.held_voices(channel, note).pop().map(|(i, _)| i),
+            RepeatedNote::Stack => None,
+        };
+
+        let started = self.notes_started;
+        let i = self.start_voice(osc, channel, note, velocity, retrigger);
+        self.held_voices(channel, note).push((i, started));
+    }
+
+    // the voices still held down by a key, oldest first
+    fn held_voices(&mut self, channel: Channel, note: Note) -> &mut Vec<(usize, u64)> {
+        let voices = &self.voices.voices;
+        let held = self.keys.entry((channel, note)).or_default();
+
+        // forget voices that have ended or been stolen since
+        held.retain(|&(i, started)| {
+            matches!(voices[i], VoiceNode::Used { started: s, held: true, .. } if s == started)
+        });
+
+        held
+    }
+
+    // starts a voice in a free slot, or restarts the one in slot, returns its index
+    fn start_voice(
+        &mut self,
+        osc: &Oscillator,
+        channel: Channel,
+        note: Note,
+        velocity: f32,
+        slot: Option<usize>,
+    ) -> usize {
+        let mut voice = V::new(note, velocity);
+        // mpe controllers send the starting expression before the note on
+        voice.modulate(&self.controls(channel));
+
+        let glide = match (self.glide, self.last_note) {
+            (Some(glide), Some(last)) => glide.start(last - semitones(note)),
+            _ => (0.0, 0.0),
+        };
+        self.last_note = Some(semitones(note));
+
+        let i = match slot {
+            Some(i) => i,
+            None => self.voices.get_free(osc, self.stealing, note).0,
+        };
+
+        self.voices.voices[i] = VoiceNode::Used {
+            voice,
+            note,
+            channel,
+            velocity,
+            pressure: 0.0,
+            held: true,
+            sostenuto: false,
+            released: false,
+            started: self.notes_started,
+            level: 0.0,
+            glide,
+            rendered: false,
+        };
+        self.notes_started += 1;
+
+        match slot {
+            // from the current level, so it doesnt click
+            Some(_) => osc.sub_osc(i, |osc| osc.retrigger()),
+            None => osc.sub_osc(i, |osc| osc.reset()),
+        }
+
+        i
+    }
+
+    // move the one voice to note, or start one if there is none
+    fn play_mono(
+        &mut self,
+        osc: &Oscillator,
+        channel: Channel,
+        note: Note,
+        velocity: f32,
+        legato: bool,
+    ) {
+        let controls = self.controls(channel);
+
+        // the one voice, or the newest if poly mode left several, even if its in its release tail
+        let sounding = self
+            .voices
+            .voices
+            .iter()
+            .enumerate()
+            .filter_map(|(i, node)| match node {
+                VoiceNode::Used {
+                    released, started, ..
+                } => Some(((!*released, *started), i)),
+                _ => None,
+            })
+            .max()
+            .map(|(_, i)| i);
+
+        let (i, node) = match sounding {
+            Some(i) => (i, &mut self.voices.voices[i]),
+            None => {
+                self.start_voice(osc, channel, note, velocity, None);
+                return;
+            }
+        };
+
+        if let VoiceNode::Used {
+            voice,
+            note: n,
+            channel: c,
+            velocity: v,
+            pressure,
+            held,
+            sostenuto,
+            released,
+            glide,
+            ..
+        } = node
+        {
+            // from wherever the last glide got to
+            if let Some(g) = self.glide {
+                *glide = g.start(semitones(*n) + glide.0 - semitones(note));
+            }
+            self.last_note = Some(semitones(note));
+
+            // a voice in its release tail isnt overlapping anything
+            if legato && !*released {
+                voice.set_note(note, velocity);
+            } else {
+                *voice = V::new(note, velocity);
+                *v = velocity;
+                // from the current level, so it doesnt click
+                osc.sub_osc(i, |osc| osc.retrigger());
+            }
+            voice.modulate(&controls);
+
+            *n = note;
+            *c = channel;
+            *pressure = 0.0;
+            *held = true;
+            *sostenuto = false;
+            *released = false;
+        }
+    }
+
+    fn note_off(&mut self, osc: &Oscillator, channel: Channel, note: Note) {
+        if let Some(mono) = self.mono {
+            let playing = mono.pick(&self.held_notes);
+            self.held_notes
+                .retain(|&(n, c, _)| (n, c) != (note, channel));
+
+            // fall back to the note that was held before, if the released one was playing
+            if playing.map(|(n, c, _)| (n, c)) == Some((note, channel)) {
+                if let Some((note, channel, velocity)) = mono.pick(&self.held_notes) {
+                    self.play_mono(osc, channel, note, velocity, mono.legato);
+                    return;
+                }
+            }
+
+            // the last key is up
+            if self.held_notes.is_empty() {
+                for node in self.voices.voices.iter_mut() {
+                    if let VoiceNode::Used { held, .. } = node {
+                        *held = false;
+                    }
+                }
+                self.release_unheld(osc);
+            }
+            return;
+        }
+
+        let held = self.held_voices(channel, note);
+        if held.is_empty() {
+            return;
+        }
+        let (i, _) = held.remove(0);
+
+        let node = &mut self.voices.voices[i];
+        if let VoiceNode::Used {
+            held, sostenuto, ..
+        } = node
+        {
+            *held = false;
+
+            if !*sostenuto && !self.sustain {
+                osc.sub_osc(i, |osc| node.release(osc));
+            }
+        }
+    }
+
+    // release every voice whose key is up, unless a pedal is holding it
+    // releasing twice does nothing, so this can be called whenever
+    fn release_unheld(&mut self, osc: &Oscillator) {
+        for (i, node) in self.voices.voices.iter_mut().enumerate() {
+            if let VoiceNode::Used {
+                held, sostenuto, ..
+            } = node
+            {
+                if !*held && !*sostenuto && !self.sustain {
+                    osc.sub_osc(i, |osc| node.release(osc));
+                }
+            }
+        }
+    }
+}
+
+impl<V: SynthTrait> Default for Part<V> {
+    fn default() -> Self {
+        Self {
+            voices: Default::default(),
+            bend_range: 2.0,
+            pitch_bend: 0.0,
+            mod_wheel: 0.0,
+            pressure: 0.0,
+            timbre: 0.5,
+            volume: 1.0,
+            expression: 1.0,
+            sustain: false,
+            sostenuto: false,
+            stealing: VoiceStealing::ReleasedFirst,
+            notes_started: 0,
+            gain_compensation: false,
+            compensation: 1.0,
+            repeated: RepeatedNote::Stack,
+            keys: Default::default(),
+            glide: None,
+            last_note: None,
+            mono: None,
+            held_notes: Vec::new(),
+            mpe: None,
+            mpe_bend_range: 48.0,
+            mpe_channels: Default::default(),
+        }
+    }
+}
+
+// a Part with its voice type hidden, so every channel can use a different one
+trait PartTrait: Send {
+    fn set_mpe(&mut self, zone: Option<MpeZone>);
+    fn handle(&mut self, osc: &Oscillator, channel: Channel, msg: &SimpleMidiMessage);
+    fn next(&mut self, osc: &Oscillator) -> Option<f32>;
+    fn is_idle(&self) -> bool;
+    fn all_notes_off(&mut self, osc: &Oscillator);
+}
+
+impl<V: VoiceTrait + Send> PartTrait for Part<V> {
+    fn set_mpe(&mut self, zone: Option<MpeZone>) {
+        self.mpe = zone;
+    }
+
+    fn is_idle(&self) -> bool {
+        self.voices.is_idle()
+    }
+
+    fn all_notes_off(&mut self, osc: &Oscillator) {
+        Part::all_notes_off(self, osc)
+    }
+
+    fn handle(&mut self, osc: &Oscillator, channel: Channel, msg: &SimpleMidiMessage) {
+        match *msg {
+            SimpleMidiMessage::NoteOn(note, velocity) => self.note_on(osc, channel, note, velocity),
+            SimpleMidiMessage::NoteOff(note, _) => self.note_off(osc, channel, note),
+            SimpleMidiMessage::PitchBend(bend) => self.pitch_bend(channel, bend),
+            SimpleMidiMessage::ChannelPressure(pressure) => {
+                self.channel_pressure(channel, pressure)
+            }
+            SimpleMidiMessage::KeyPressure(note, pressure) => {
+                self.key_pressure(channel, note, pressure)
+            }
+            SimpleMidiMessage::ControlChange(cc, value) => {
+                self.control_change(osc, channel, cc, value)
+            }
+        }
+    }
+
+    fn next(&mut self, osc: &Oscillator) -> Option<f32> {
+        // only for the channels that are playing, usually just one
+        let mut controls = [None; 16];
+        for node in self.voices.voices.iter() {
+            if let VoiceNode::Used { channel, .. } = node {
+                let i = channel.index() as usize;
+                if controls[i].is_none() {
+                    controls[i] = Some(self.controls(*channel));
+                }
+            }
+        }
+
+        for node in self.voices.voices.iter_mut() {
+            if let VoiceNode::Used {
+                voice,
+                channel,
+                pressure,
+                glide,
+                ..
+            } = node
+            {
+                let controls = controls[channel.index() as usize].unwrap();
+                let bend = if glide.0 == 0.0 {
+                    controls.bend
+                } else {
+                    controls.bend * 2.0f32.powf(glide.0 / 12.0)
+                };
+                voice.modulate(&Controls {
+                    bend,
+                    pressure: controls.pressure.max(*pressure),
+                    ..controls
+                });
+                *glide = Glide::next(*glide);
+            }
+        }
+
+        let out = self.voices.next(osc)?;
+
+        // uncorrelated voices add up in power, so a chord of n is sqrt(n) times louder
+        let target = if self.gain_compensation {
+            1.0 / (self.voices.active.max(1) as f32).sqrt()
+        } else {
+            1.0
+        };
+        self.compensation = lerp(0.001, self.compensation, target);
+
+        Some(out * self.compensation * self.volume * self.expression)
+    }
+}
+
+// multitimbral, each midi channel plays its own Part
+// channels without a part are ignored
+pub struct MidiSynth {
+    input: MidiInput,
+    parts: Vec<Option<Box<dyn PartTrait>>>, // indexed by channel
+    routes: [usize; 16],                    // which part each channel plays, normally itself
+    rpn: [(u8, u8); 16], // last registered parameter number selected on each channel
+    gain: f32,           // master gain, for the mix of every part
+    clock: u64,          // samples played
+    // seconds every message is delayed by, so ones that arrive a little late still play on time
+    // 0 plays them as soon as they arrive, with however much jitter the audio buffer adds
+    latency: f32,
+    time_offset: Option<i64>, // add to a midi timestamp in samples to get when to play it
+    pending: VecDeque<(u64, Channel, SimpleMidiMessage)>, // (sample to play at, ..), in order
+}
+
+impl MidiSynth {
+    // every channel plays a default Part<V>
+    pub fn new<V: VoiceTrait + Send + 'static>() -> Self {
+        Self {
+            input: Default::default(),
+            parts: (0..16)
+                .map(|_| Some(Box::new(Part::<V>::default()) as Box<dyn PartTrait>))
+                .collect(),
+            routes: [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+            rpn: [(127, 127); 16], // null rpn
+            gain: 0.3,
+            clock: 0,
+            latency: 0.02,
+            time_offset: None,
+            pending: VecDeque::new(),
+        }
+    }
+
+    // the sample a message should be played at, from its midi timestamp
+    fn schedule(&mut self, time: u64) -> u64 {
+        let at = (time * BITRATE as u64 / 1_000_000) as i64;
+        let now = self.clock as i64;
+        let latency = (self.latency * BITRATE_F) as i64;
+
+        let mut scheduled = at + *self.time_offset.get_or_insert(now + latency - at);
+
+        // the midi and audio clocks have drifted apart, or the audio stalled
+        if scheduled < now || scheduled > now + latency * 4 {
+            self.time_offset = Some(now + latency - at);
+            scheduled = now + latency;
+        }
+
+        // dont let moving the offset reorder messages
+        if let Some(&(last, _, _)) = self.pending.back() {
+            scheduled = scheduled.max(last as i64);
+        }
+
+        scheduled as u64
+    }
+
+    fn handle(&mut self, osc: &Oscillator, channel: Channel, msg: SimpleMidiMessage) {
+        if let SimpleMidiMessage::ControlChange(cc, value) = msg {
+            self.registered_parameter(osc, channel, cc, value);
+        }
+
+        let i = self.routes[channel.index() as usize];
+        if let Some(part) = self.parts[i].as_mut() {
+            osc.sub_osc(i, |osc| part.handle(osc, channel, &msg));
+        }
+    }
+
+    // the part plays every channel in the zone, and goes on the zone's master channel
+    pub fn with_mpe_part<V: VoiceTrait + Send + 'static>(
+        mut self,
+        zone: MpeZone,
+        part: Part<V>,
+    ) -> Self {
+        self.parts[zone.master.index() as usize] = Some(Box::new(part));
+        self.route_mpe_zone(zone);
+        self
+    }
+
+    // a zone with no members turns mpe back off
+    fn set_mpe_zone(&mut self, osc: &Oscillator, zone: MpeZone) {
+        let old_routes = self.routes;
+        self.route_mpe_zone(zone);
+
+        // a note on a channel that moved would get its note off sent to a different part
+        let mut moved = [false; 16];
+        for (&old, &new) in old_routes.iter().zip(self.routes.iter()) {
+            if old != new {
+                moved[old] = true;
+            }
+        }
+
+        for (i, part) in self.parts.iter_mut().enumerate() {
+            if let (true, Some(part)) = (moved[i], part.as_mut()) {
+                osc.sub_osc(i, |osc| part.all_notes_off(osc));
+            }
+        }
+    }
+
+    fn route_mpe_zone(&mut self, zone: MpeZone) {
+        let master = zone.master.index() as usize;
+
+        // undo the last zone on this master
+        for (i, route) in self.routes.iter_mut().enumerate() {
+            if *route == master {
+                *route = i;
+            }
+        }
+
+        for channel in zone.channels() {
+            self.routes[channel.index() as usize] = master;
+        }
+
+        if let Some(part) = self.parts[master].as_mut() {
+            part.set_mpe(if zone.members > 0 { Some(zone) } else { None });
+        }
+    }
+
+    // mpe controllers announce their zones with rpn 6 on the master channel
+    fn registered_parameter(
+        &mut self,
+        osc: &Oscillator,
+        channel: Channel,
+        cc: ControlFunction,
+        value: f32,
+    ) {
+        let i = channel.index() as usize;
+        let value = (value * 127.0).round() as u8;
+
+        match cc {
+            ControlFunction::REGISTERED_PARAMETER_NUMBER_MSB => self.rpn[i].0 = value,
+            ControlFunction::REGISTERED_PARAMETER_NUMBER_LSB => self.rpn[i].1 = value,
+            ControlFunction::DATA_ENTRY_MSB if self.rpn[i] == (0, 6) => match channel {
+                Channel::Ch1 => self.set_mpe_zone(osc, MpeZone::lower(value.min(15))),
+                Channel::Ch16 => self.set_mpe_zone(osc, MpeZone::upper(value.min(15))),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    pub fn with_part<V: VoiceTrait + Send + 'static>(
+        mut self,
+        channel: Channel,
+        part: Part<V>,
+    ) -> Self {
+        let i = channel.index() as usize;
+        self.routes[i] = i;
+        self.parts[i] = Some(Box::new(part));
+        self
+    }
+
+    pub fn with_gain(mut self, gain: f32) -> Self {
+        self.gain = gain;
+        self
+    }
+
+    pub fn with_latency(mut self, latency: f32) -> Self {
+        assert!(latency >= 0.0);
+        self.latency = latency;
+        self
+    }
+
+    pub fn without_part(mut self, channel: Channel) -> Self {
+        self.parts[channel.index() as usize] = None;
+        self
+    }
+}
+
+impl SynthTrait for MidiSynth {
+    fn next(&mut self, osc: &Oscillator) -> Option<f32> {
+        // take everything that has come in, and work out when to play it
+        loop {
+            match self.input.try_recv() {
+                Ok((time, channel, msg)) => {
+                    let at = self.schedule(time);
+                    self.pending.push_back((at, channel, msg));
+                }
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => unreachable!(), // connection is not closed while MidiSynth is not dropped
+            }
+        }
+
+        // then play everything due this sample, so chords start together
+        while let Some(&(at, _, _)) = self.pending.front() {
+            if at > self.clock {
+                break;
+            }
+
+            let (_, channel, msg) = self.pending.pop_front().unwrap();
+            self.handle(osc, channel, msg);
+        }
+
+        self.clock += 1;
+
+        let out = self
+            .parts
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(i, part)| Some((i, part.as_mut()?)))
+            // most channels are silent most of the time
+            .filter(|(_, part)| !part.is_idle())
+            .filter_map(|(i, part)| osc.sub_osc(i, |osc| part.next(osc)))
+            .sum::<f32>();
+
+        Some(out * self.gain)
+    }
+}
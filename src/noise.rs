@@ -0,0 +1,76 @@
+use rand::{Error, RngCore, SeedableRng};
+
+// splitmix64, small enough to be Copy so it can live in Oscillator::unique_caller
+// not suitable for anything but audio
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoiseRng(u64);
+
+impl RngCore for NoiseRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl SeedableRng for NoiseRng {
+    type Seed = [u8; 8];
+
+    fn from_seed(seed: Self::Seed) -> Self {
+        Self(u64::from_le_bytes(seed))
+    }
+}
+
+// filter state for paul kellet's pink noise approximation
+// accurate to within 0.05 db above 9.2 hz at 44.1 khz
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PinkFilter([f32; 7]);
+
+impl PinkFilter {
+    pub fn next(&mut self, white: f32) -> f32 {
+        let b = &mut self.0;
+
+        b[0] = 0.99886 * b[0] + white * 0.0555179;
+        b[1] = 0.99332 * b[1] + white * 0.0750759;
+        b[2] = 0.96900 * b[2] + white * 0.153852;
+        b[3] = 0.86650 * b[3] + white * 0.3104856;
+        b[4] = 0.55000 * b[4] + white * 0.5329522;
+        b[5] = -0.7616 * b[5] - white * 0.0168980;
+        let out = b[0] + b[1] + b[2] + b[3] + b[4] + b[5] + b[6] + white * 0.5362;
+        b[6] = white * 0.115926;
+
+        // roughly back to -1..1
+        out * 0.11
+    }
+}
+
+// leaky integrator, so it wanders like brownian motion without drifting off forever
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BrownFilter(f32);
+
+impl BrownFilter {
+    pub fn next(&mut self, white: f32) -> f32 {
+        self.0 = (self.0 + 0.02 * white) / 1.02;
+
+        // roughly back to -1..1
+        self.0 * 3.5
+    }
+}
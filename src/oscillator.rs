@@ -1,11 +1,16 @@
 use std::any::{Any, TypeId};
 use std::cell::{Ref, RefCell, RefMut};
-use std::f32::consts::TAU;
+use std::f32::consts::{FRAC_PI_4, TAU};
 use std::ops::DerefMut;
 
+use rand::{Rng, SeedableRng};
 use rustc_hash::FxHashMap;
 
+use crate::additive::Partial;
+use crate::envelope::{Envelope, EnvelopeParams};
+use crate::noise::{BrownFilter, NoiseRng, PinkFilter};
 use crate::util::{Index, BITRATE_F};
+use crate::wavetable::Wavetable;
 use crate::{ADSRParams, ADSR};
 
 type HashMap<T> = FxHashMap<Index, T>;
@@ -40,6 +45,12 @@ impl Clone for AnyHashMap {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    Hard, // slave phase restarts from zero
+    Soft, // slave phase reverses direction
+}
+
 #[derive(Default, Clone)]
 pub struct Oscillator {
     hashmap_meta: RefCell<FxHashMap<TypeId, AnyHashMap>>, // effective signature: HashMap<T::TypeId, HashMap<T>>
@@ -118,6 +129,25 @@ impl Oscillator {
         self.get(freq, 0.0, 0.0, TAU).sin()
     }
 
+    // sine with its phase offset by modulation (in radians), for phase modulation synthesis
+    // feedback feeds the average of the last two outputs back into the phase, like a dx7 operator
+    #[track_caller]
+    pub fn get_pm(&self, freq: f32, modulation: f32, feedback: f32) -> f32 {
+        let dt = freq / BITRATE_F;
+
+        // first sample: phase and feedback history are all zero
+        let mut out = modulation.sin();
+
+        self.unique_caller((0.0, 0.0, 0.0), |(t, y1, y2): &mut (f32, f32, f32)| {
+            *t = (*t + dt).rem_euclid(1.0);
+            out = (*t * TAU + modulation + feedback * (*y1 + *y2) / 2.0).sin();
+            *y2 = *y1;
+            *y1 = out;
+        });
+
+        out
+    }
+
     #[track_caller]
     pub fn get_tri(&self, freq: f32) -> f32 {
         let x = self.get(freq, 0.0, -1.0, 3.0);
@@ -134,6 +164,211 @@ impl Oscillator {
         self.get(freq, 0.0, -1.0, 1.0)
     }
 
+    #[track_caller]
+    pub fn get_square(&self, freq: f32) -> f32 {
+        if self.get(freq, 0.0, 0.0, 1.0) < 0.5 {
+            1.0
+        } else {
+            -1.0
+        }
+    }
+
+    // band-limited versions of the above, using polyblep to smooth out the discontinuities
+    // these cost a bit more but dont alias when played high up
+
+    #[track_caller]
+    pub fn get_saw_blep(&self, freq: f32) -> f32 {
+        let dt = (freq / BITRATE_F).abs();
+        let t = self.get(freq, 0.0, 0.0, 1.0);
+
+        saw_blep(t, dt)
+    }
+
+    #[track_caller]
+    pub fn get_square_blep(&self, freq: f32) -> f32 {
+        self.get_pulse(freq, 0.5)
+    }
+
+    // width is the fraction of the cycle spent high (0 < width < 1)
+    // only the phase is stored, so width can change every sample without glitching
+    #[track_caller]
+    pub fn get_pulse(&self, freq: f32, width: f32) -> f32 {
+        let dt = (freq / BITRATE_F).abs();
+        let t = self.get(freq, 0.0, 0.0, 1.0);
+
+        pulse_blep(t, dt, width)
+    }
+
+    // integrates a band-limited square, so the phase and the integrator share one entry
+    #[track_caller]
+    pub fn get_tri_blep(&self, freq: f32) -> f32 {
+        let dt = (freq / BITRATE_F).abs();
+
+        self.unique_caller((0.0, -1.0), |(t, y): &mut (f32, f32)| {
+            *t = (*t + freq / BITRATE_F).rem_euclid(1.0);
+            // leaky so that any dc offset dies out instead of accumulating forever
+            *y = 4.0 * dt * pulse_blep(*t, dt, 0.5) + (1.0 - dt) * *y;
+        })
+        .1
+    }
+
+    // morph picks the frame, from the first (0) to the last (1), and can change every sample
+    #[track_caller]
+    pub fn get_wavetable(&self, table: &Wavetable, freq: f32, morph: f32) -> f32 {
+        let t = self.get(freq, 0.0, 0.0, 1.0);
+
+        table.sample(t, freq, morph)
+    }
+
+    // phase (0 <= t < 1) of an oscillator meant to drive get_sync_slave
+    // also returns Some(x) on the sample the phase wraps, where x is how far (0 <= x < 1)
+    // past the wrap this sample lands, so the slave can be reset between samples
+    #[track_caller]
+    pub fn get_sync_master(&self, freq: f32) -> (f32, Option<f32>) {
+        let dt = freq / BITRATE_F;
+        let mut wrapped = None;
+
+        let t = self.unique_caller(0.0, |t: &mut f32| {
+            *t += dt;
+            if *t >= 1.0 {
+                *t -= 1.0;
+                wrapped = Some(*t / dt);
+            }
+        });
+
+        (t, wrapped)
+    }
+
+    // phase (0 <= t < 1) of an oscillator synced to a master from get_sync_master
+    // feed it the master's wrap event every sample, eg:
+    //     let (_, sync) = osc.get_sync_master(110.0);
+    //     let saw = osc.get_sync_slave(290.0, sync, SyncMode::Hard) * 2.0 - 1.0;
+    #[track_caller]
+    pub fn get_sync_slave(&self, freq: f32, sync: Option<f32>, mode: SyncMode) -> f32 {
+        let dt = freq / BITRATE_F;
+
+        self.unique_caller((0.0, 1.0), |(t, dir): &mut (f32, f32)| match (sync, mode) {
+            (Some(x), SyncMode::Hard) => {
+                *t = x * dt;
+                *dir = 1.0;
+            }
+            (Some(_), SyncMode::Soft) => {
+                *dir = -*dir;
+                *t = (*t + *dir * dt).rem_euclid(1.0);
+            }
+            (None, _) => *t = (*t + *dir * dt).rem_euclid(1.0),
+        })
+        .0
+    }
+
+    // stack of detuned band-limited saws, returns (left, right)
+    // detune is the total spread in semitones, stereo (0 to 1) is how far apart they are panned
+    // the starting phases are random, but always the same for a given seed
+    #[track_caller]
+    pub fn get_supersaw(
+        &self,
+        freq: f32,
+        voices: usize,
+        detune: f32,
+        stereo: f32,
+        seed: u64,
+    ) -> (f32, f32) {
+        let loc = Index::location();
+        let gain = 1.0 / (voices as f32).sqrt();
+
+        // every call site gets its own sub oscillator, with one sub oscillator inside per voice
+        self.sub_osc(loc, |osc| {
+            let mut rng = NoiseRng::seed_from_u64(seed);
+            let (mut left, mut right) = (0.0, 0.0);
+
+            for i in 0..voices {
+                // -1 to 1 across the stack
+                let x = if voices > 1 {
+                    2.0 * i as f32 / (voices - 1) as f32 - 1.0
+                } else {
+                    0.0
+                };
+                let start: f32 = rng.gen();
+
+                let freq = freq * 2.0f32.powf(x * detune / 2.0 / 12.0);
+                let dt = (freq / BITRATE_F).abs();
+                let t = osc.sub_osc(i, |osc| osc.get(freq, start, 0.0, 1.0));
+                let out = saw_blep(t, dt) * gain;
+
+                // equal power panning
+                let (r, l) = ((x * stereo + 1.0) * FRAC_PI_4).sin_cos();
+                left += out * l;
+                right += out * r;
+            }
+
+            (left, right)
+        })
+    }
+
+    // sum of sines, partials above nyquist are skipped so they dont alias
+    // each partial keeps its own phase and envelope, so the list should stay the same between calls
+    #[track_caller]
+    pub fn get_additive(&self, freq: f32, partials: &[Partial]) -> f32 {
+        let loc = Index::location();
+
+        self.sub_osc(loc, |osc| {
+            partials
+                .iter()
+                .enumerate()
+                .map(|(i, partial)| {
+                    osc.sub_osc(i, |osc| {
+                        let env = match &partial.adsr {
+                            Some(adsr) => osc.adsr(adsr.clone()).next().unwrap_or(0.0),
+                            None => 1.0,
+                        };
+
+                        let freq = freq * partial.ratio;
+                        if freq.abs() >= BITRATE_F / 2.0 {
+                            return 0.0;
+                        }
+
+                        osc.get_sin(freq) * partial.amplitude * env
+                    })
+                })
+                .sum()
+        })
+    }
+
+    // noise sources, each call site has its own rng so they are deterministic for a given seed
+    // like start in get(), the seed is only used the first time a call site runs
+
+    #[track_caller]
+    pub fn get_white_noise(&self, seed: u64) -> f32 {
+        let mut out = 0.0;
+        self.unique_caller(NoiseRng::seed_from_u64(seed), |rng: &mut NoiseRng| {
+            out = rng.gen_range(-1.0..1.0);
+        });
+
+        out
+    }
+
+    #[track_caller]
+    pub fn get_pink_noise(&self, seed: u64) -> f32 {
+        let mut out = 0.0;
+        self.unique_caller(
+            (NoiseRng::seed_from_u64(seed), PinkFilter::default()),
+            |(rng, pink): &mut (NoiseRng, PinkFilter)| out = pink.next(rng.gen_range(-1.0..1.0)),
+        );
+
+        out
+    }
+
+    #[track_caller]
+    pub fn get_brown_noise(&self, seed: u64) -> f32 {
+        let mut out = 0.0;
+        self.unique_caller(
+            (NoiseRng::seed_from_u64(seed), BrownFilter::default()),
+            |(rng, brown): &mut (NoiseRng, BrownFilter)| out = brown.next(rng.gen_range(-1.0..1.0)),
+        );
+
+        out
+    }
+
     #[track_caller]
     pub fn rising_edge(&self, val: f32) -> bool {
         let mut last_signum = 0.0;
@@ -155,8 +390,12 @@ impl Oscillator {
         let loc = Index::location();
         let mut hashmap: RefMut<HashMap<RefCell<ADSR>>> = self.hashmap_mut();
 
-        if hashmap.get(&loc).is_none() {
-            hashmap.insert(loc, RefCell::new(adsr_params.build()));
+        // after a retrigger, eg a new note on the same voice, take the new note's params
+        match hashmap.get(&loc) {
+            Some(adsr) => adsr.borrow_mut().take_params(adsr_params),
+            None => {
+                hashmap.insert(loc, RefCell::new(adsr_params.build()));
+            }
         }
 
         ADSRImposter(self, loc)
@@ -173,11 +412,81 @@ impl Oscillator {
         func(adsr.deref_mut())
     }
 
+    // like adsr(), but for a breakpoint envelope
+    #[track_caller]
+    pub fn envelope(&self, params: EnvelopeParams) -> EnvelopeImposter<'_> {
+        let loc = Index::location();
+        let mut hashmap: RefMut<HashMap<RefCell<Envelope>>> = self.hashmap_mut();
+
+        match hashmap.get(&loc) {
+            Some(envelope) => envelope.borrow_mut().take_params(params),
+            None => {
+                hashmap.insert(loc, RefCell::new(params.build()));
+            }
+        }
+
+        EnvelopeImposter(self, loc)
+    }
+
+    fn envelope_impl<T, U>(&self, loc: Index, func: T) -> U
+    where
+        T: FnOnce(&mut Envelope) -> U,
+    {
+        let hashmap: Ref<HashMap<RefCell<Envelope>>> = self.hashmap_ref();
+        let envelope = Ref::map(hashmap, |hashmap| hashmap.get(&loc).unwrap());
+        let mut envelope = envelope.borrow_mut();
+
+        func(envelope.deref_mut())
+    }
+
     pub fn release(&self) {
         // todo: mark certain adsrs / oscillators as per-note, as opposed to lfo
         for (_, adsr) in self.hashmap_mut::<RefCell<ADSR>>().iter_mut() {
             adsr.borrow_mut().release();
         }
+
+        for (_, envelope) in self.hashmap_mut::<RefCell<Envelope>>().iter_mut() {
+            envelope.borrow_mut().release();
+        }
+
+        // envelopes inside sub oscillators (eg fm operators) belong to the same note
+        for (_, osc) in self.hashmap_mut::<Oscillator>().iter() {
+            osc.release();
+        }
+    }
+
+    // like release, but starts every envelope's attack again from its current level
+    pub fn retrigger(&self) {
+        for (_, adsr) in self.hashmap_mut::<RefCell<ADSR>>().iter_mut() {
+            adsr.borrow_mut().retrigger();
+        }
+
+        for (_, envelope) in self.hashmap_mut::<RefCell<Envelope>>().iter_mut() {
+            envelope.borrow_mut().retrigger();
+        }
+
+        for (_, osc) in self.hashmap_mut::<Oscillator>().iter() {
+            osc.retrigger();
+        }
+    }
+
+    // if any envelope is still in its attack, eg so voice stealing can leave it alone
+    pub fn is_attacking(&self) -> bool {
+        // one at a time, each borrow of hashmap_meta has to end before the next
+        let adsrs = self
+            .hashmap_mut::<RefCell<ADSR>>()
+            .values()
+            .any(|adsr| adsr.borrow().is_attack());
+        let envelopes = self
+            .hashmap_mut::<RefCell<Envelope>>()
+            .values()
+            .any(|envelope| envelope.borrow().is_rising());
+        let sub_oscs = self
+            .hashmap_mut::<Oscillator>()
+            .values()
+            .any(|osc| osc.is_attacking());
+
+        adsrs || envelopes || sub_oscs
     }
 
     pub fn reset(&self) {
@@ -198,6 +507,33 @@ impl Oscillator {
     }
 }
 
+// polyblep residual for a discontinuity of height 2 at t = 0
+// subtract it for a falling edge, add it for a rising edge
+// t is the phase (0 <= t < 1), dt is the phase increment per sample
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        t + t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + t + t + 1.0
+    } else {
+        0.0
+    }
+}
+
+fn saw_blep(t: f32, dt: f32) -> f32 {
+    2.0 * t - 1.0 - poly_blep(t, dt)
+}
+
+fn pulse_blep(t: f32, dt: f32, width: f32) -> f32 {
+    // keep both edges at least a sample apart, or their corrections overlap
+    let width = width.min(1.0 - dt).max(dt);
+    let naive = if t < width { 1.0 } else { -1.0 };
+
+    naive + poly_blep(t, dt) - poly_blep((t + 1.0 - width) % 1.0, dt)
+}
+
 pub struct ADSRImposter<'a>(&'a Oscillator, Index);
 
 impl<'a> ADSRImposter<'a> {
@@ -222,7 +558,44 @@ impl<'a> ADSRImposter<'a> {
         self.inner(|adsr| adsr.release())
     }
 
+    pub fn retrigger(&mut self) {
+        self.inner(|adsr| adsr.retrigger())
+    }
+
     pub fn next(&mut self) -> Option<f32> {
         self.inner(|adsr| adsr.next())
     }
 }
+
+pub struct EnvelopeImposter<'a>(&'a Oscillator, Index);
+
+impl<'a> EnvelopeImposter<'a> {
+    fn inner<T: FnOnce(&mut Envelope) -> U, U>(&self, func: T) -> U {
+        self.0.envelope_impl(self.1, func)
+    }
+
+    pub fn reset(&mut self) {
+        self.inner(|envelope| envelope.reset())
+    }
+
+    pub fn is_end(&self) -> bool {
+        self.inner(|envelope| envelope.is_end())
+    }
+
+    // if true, the ending of this envelope can be cut short (interrupted)
+    pub fn is_done(&self) -> bool {
+        self.inner(|envelope| envelope.is_done())
+    }
+
+    pub fn release(&mut self) {
+        self.inner(|envelope| envelope.release())
+    }
+
+    pub fn retrigger(&mut self) {
+        self.inner(|envelope| envelope.retrigger())
+    }
+
+    pub fn next(&mut self) -> Option<f32> {
+        self.inner(|envelope| envelope.next())
+    }
+}
@@ -0,0 +1,124 @@
+use std::f32::consts::PI;
+
+use rand::{Rng, SeedableRng};
+use wmidi::Note;
+
+use crate::adsr::ADSRParams;
+use crate::noise::NoiseRng;
+use crate::oscillator::Oscillator;
+use crate::synth_template::{SynthTrait, VoiceTrait};
+use crate::util::{clamp01, lerp, BITRATE_F};
+use crate::velocity::VelocityCurve;
+
+// karplus-strong plucked string
+// a delay line one period long is filled with noise, then fed back through a lowpass,
+// so the high harmonics die out first like on a real string
+#[derive(Clone)]
+pub struct Pluck {
+    pub freq: f32,
+    pub amplitude: f32,
+    // the lowpass alone damps high notes, so they cant ring for as long as a large decay asks
+    pub decay: f32,      // seconds for the string to fall by 60 db
+    pub brightness: f32, // 0 to 1, how much high end the pluck starts with
+    pub release: f32,    // seconds to damp the string after release
+    pub seed: u64,
+
+    buffer: Vec<f32>,
+    pos: usize,
+    gain: f32,                // loop gain, applied once per trip around the delay line
+    last: f32,                // previous sample, for the lowpass
+    allpass: (f32, f32, f32), // (coefficient, last input, last output)
+}
+
+impl Pluck {
+    pub fn from_freq(freq: f32) -> Self {
+        Self {
+            freq,
+            amplitude: 1.0,
+            decay: 4.0,
+            brightness: 0.8,
+            release: 0.1,
+            seed: 0,
+            buffer: Vec::new(),
+            pos: 0,
+            gain: 1.0,
+            last: 0.0,
+            allpass: (0.0, 0.0, 0.0),
+        }
+    }
+
+    // fill the delay line and work out the tuning
+    // done on the first sample so the public fields can be changed after from_freq()
+    fn pluck(&mut self) {
+        // the averaging lowpass delays by half a sample, the allpass makes up the fraction
+        let period = BITRATE_F / self.freq;
+        let len = ((period - 0.5).floor() as usize).max(1);
+        let frac = (period - 0.5 - len as f32).max(0.0);
+
+        self.allpass = ((1.0 - frac) / (1.0 + frac), 0.0, 0.0);
+        // the lowpass loses some of the fundamental every trip too, make up for that
+        // but not past 1, or the low end would build up instead of dying out
+        let lowpass = (PI * self.freq / BITRATE_F).cos();
+        self.gain = (0.001f32.powf(1.0 / (self.decay * self.freq)) / lowpass).min(1.0);
+
+        // excitation, noise smoothed more the darker it should be
+        let mut rng = NoiseRng::seed_from_u64(self.seed);
+        let smoothing = 1.0 - clamp01(self.brightness);
+        let mut last = 0.0;
+
+        self.buffer = (0..len)
+            .map(|_| {
+                last = lerp(smoothing, rng.gen_range(-1.0..1.0), last);
+                last * self.amplitude
+            })
+            .collect();
+
+        // remove dc, or it rings forever as a constant offset
+        let mean = self.buffer.iter().sum::<f32>() / len as f32;
+        self.buffer.iter_mut().for_each(|x| *x -= mean);
+    }
+}
+
+impl SynthTrait for Pluck {
+    fn next(&mut self, osc: &Oscillator) -> Option<f32> {
+        if self.buffer.is_empty() {
+            self.pluck();
+        }
+
+        // hold until released, then damp quickly
+        let vol = osc
+            .adsr(ADSRParams {
+                sustain_percent: 1.0,
+                sustain_length: self.decay,
+                release_length: self.release,
+                ..ADSRParams::zero()
+            })
+            .next()?;
+
+        let x = self.buffer[self.pos];
+
+        let lowpass = (x + self.last) / 2.0;
+        self.last = x;
+
+        let (c, x1, y1) = self.allpass;
+        let y = c * lowpass + x1 - c * y1;
+        self.allpass = (c, lowpass, y);
+
+        self.buffer[self.pos] = y * self.gain;
+        self.pos = (self.pos + 1) % self.buffer.len();
+
+        Some(x * vol)
+    }
+}
+
+impl VoiceTrait for Pluck {
+    fn new(note: Note, velocity: f32) -> Self {
+        // harder plucks are louder and brighter
+        Self {
+            seed: u8::from(note) as u64,
+            amplitude: VelocityCurve::Soft.apply(velocity),
+            brightness: VelocityCurve::Linear.scale(velocity, 0.3, 1.0),
+            ..Self::from_freq(note.to_freq_f32())
+        }
+    }
+}
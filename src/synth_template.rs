@@ -1,4 +1,5 @@
 use rodio::Source;
+use wmidi::Note;
 
 use crate::oscillator::Oscillator;
 use crate::util::{BITRATE, BITRATE_F};
@@ -20,6 +21,44 @@ pub trait SynthTrait {
     }
 }
 
+// a voice that can be started for a note, eg by MidiSynth
+// velocity is 0 to 1, see VelocityCurve for shaping it
+pub trait VoiceTrait: SynthTrait {
+    fn new(note: Note, velocity: f32) -> Self;
+
+    // called before every sample with the current controller values
+    fn modulate(&mut self, _controls: &Controls) {}
+
+    // change to another note without restarting, for legato in mono mode
+    // by default starts a new voice, the oscillator's envelopes carry on either way
+    fn set_note(&mut self, note: Note, velocity: f32)
+    where
+        Self: Sized,
+    {
+        *self = Self::new(note, velocity);
+    }
+}
+
+// live controller values for a voice
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Controls {
+    pub bend: f32,      // multiply the voice's frequency by this
+    pub mod_wheel: f32, // 0 to 1
+    pub pressure: f32,  // 0 to 1, aftertouch
+    pub timbre: f32,    // 0 to 1, centered on 0.5, mpe slide (cc74)
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Self {
+            bend: 1.0,
+            mod_wheel: 0.0,
+            pressure: 0.0,
+            timbre: 0.5,
+        }
+    }
+}
+
 impl<T: SynthTrait> From<T> for SynthRoot<T> {
     fn from(v: T) -> Self {
         SynthRoot::new(v)
@@ -0,0 +1,30 @@
+use crate::util::{clamp01, lerp};
+
+// how a note's velocity (0 to 1) maps to how hard it plays (0 to 1)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VelocityCurve {
+    Linear,
+    Soft,       // quiet notes come out louder, for light players
+    Hard,       // quiet notes come out quieter, for heavy players
+    Fixed(f32), // ignore velocity
+    Power(f32), // velocity ^ x, Soft and Hard are 0.5 and 2
+}
+
+impl VelocityCurve {
+    pub fn apply(self, velocity: f32) -> f32 {
+        let velocity = clamp01(velocity);
+
+        match self {
+            Self::Linear => velocity,
+            Self::Soft => velocity.sqrt(),
+            Self::Hard => velocity * velocity,
+            Self::Fixed(x) => x,
+            Self::Power(x) => velocity.powf(x),
+        }
+    }
+
+    // velocity to (a < ans < b), eg for envelope times or filter cutoff
+    pub fn scale(self, velocity: f32, a: f32, b: f32) -> f32 {
+        lerp(self.apply(velocity), a, b)
+    }
+}
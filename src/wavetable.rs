@@ -0,0 +1,193 @@
+use std::f32::consts::TAU;
+use std::path::Path;
+
+use hound::{Error as HoundError, Result as HoundResult, SampleFormat, WavReader};
+
+use crate::util::{clamp01, lerp, BITRATE_F};
+
+// every frame is resampled to this length, must be a power of 2 for the fft
+pub const TABLE_SIZE: usize = 2048;
+// level n of the mip map keeps only the harmonics up to (TABLE_SIZE / 2) >> n
+const MIP_LEVELS: usize = 11;
+
+// one single-cycle waveform, stored once per mip level
+#[derive(Clone)]
+struct Frame {
+    levels: Vec<Vec<f32>>,
+}
+
+impl Frame {
+    fn new(cycle: &[f32]) -> Self {
+        let mut re = resample(cycle, TABLE_SIZE);
+        let mut im = vec![0.0; TABLE_SIZE];
+        fft(&mut re, &mut im, false);
+
+        let levels = (0..MIP_LEVELS)
+            .map(|level| {
+                let max_harmonic = (TABLE_SIZE / 2) >> level;
+                let mut re = re.clone();
+                let mut im = im.clone();
+
+                // drop everything above max_harmonic, mirrored for the negative frequencies
+                for bin in (max_harmonic + 1)..(TABLE_SIZE - max_harmonic) {
+                    re[bin] = 0.0;
+                    im[bin] = 0.0;
+                }
+
+                fft(&mut re, &mut im, true);
+                re
+            })
+            .collect();
+
+        Self { levels }
+    }
+
+    fn sample(&self, level: usize, t: f32) -> f32 {
+        let table = &self.levels[level];
+        let x = t * TABLE_SIZE as f32;
+        let i = x as usize % TABLE_SIZE;
+
+        lerp(x.fract(), table[i], table[(i + 1) % TABLE_SIZE])
+    }
+}
+
+#[derive(Clone)]
+pub struct Wavetable {
+    frames: Vec<Frame>,
+}
+
+impl Wavetable {
+    // each entry of cycles is one frame, of any length
+    pub fn new(cycles: &[&[f32]]) -> Self {
+        assert!(!cycles.is_empty());
+
+        Self {
+            frames: cycles.iter().map(|cycle| Frame::new(cycle)).collect(),
+        }
+    }
+
+    // loads the first channel of a wav file
+    // frame_size is the length of each frame for multi-frame tables (2048 for serum tables),
+    // or None if the whole file is a single cycle
+    pub fn load<P: AsRef<Path>>(filename: P, frame_size: Option<usize>) -> HoundResult<Self> {
+        let mut reader = WavReader::open(filename)?;
+        let spec = reader.spec();
+        let channels = spec.channels as usize;
+
+        let samples: Vec<f32> = match spec.sample_format {
+            SampleFormat::Float => reader
+                .samples::<f32>()
+                .step_by(channels)
+                .collect::<Result<_, _>>()?,
+            SampleFormat::Int => {
+                let max = (1u32 << (spec.bits_per_sample - 1)) as f32;
+                reader
+                    .samples::<i32>()
+                    .step_by(channels)
+                    .map(|x| x.map(|x| x as f32 / max))
+                    .collect::<Result<_, _>>()?
+            }
+        };
+
+        if samples.is_empty() {
+            return Err(HoundError::FormatError("wavetable has no samples"));
+        }
+
+        let frame_size = frame_size.unwrap_or(samples.len());
+        if frame_size == 0 || !samples.len().is_multiple_of(frame_size) {
+            return Err(HoundError::FormatError(
+                "wavetable length is not a whole number of frames",
+            ));
+        }
+
+        let cycles: Vec<&[f32]> = samples.chunks_exact(frame_size).collect();
+
+        Ok(Self::new(&cycles))
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    // t is the phase (0 <= t < 1), morph sweeps from the first frame (0) to the last (1)
+    // freq picks the mip level, so that no harmonic lands above nyquist
+    pub fn sample(&self, t: f32, freq: f32, morph: f32) -> f32 {
+        let highest = freq.abs() * (TABLE_SIZE / 2) as f32 / (BITRATE_F / 2.0);
+        let level = (highest.log2().ceil().max(0.0) as usize).min(MIP_LEVELS - 1);
+
+        let x = clamp01(morph) * (self.frames.len() - 1) as f32;
+        let i = x as usize;
+        let j = (i + 1).min(self.frames.len() - 1);
+
+        lerp(
+            x.fract(),
+            self.frames[i].sample(level, t),
+            self.frames[j].sample(level, t),
+        )
+    }
+}
+
+// linear interpolation to a new length, treating the input as one cycle
+fn resample(cycle: &[f32], len: usize) -> Vec<f32> {
+    assert!(!cycle.is_empty());
+
+    (0..len)
+        .map(|i| {
+            let x = i as f32 * cycle.len() as f32 / len as f32;
+            let j = x as usize;
+            lerp(x.fract(), cycle[j], cycle[(j + 1) % cycle.len()])
+        })
+        .collect()
+}
+
+// in place radix 2 fft, re.len() must be a power of 2
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    assert!(n.is_power_of_two() && im.len() == n);
+
+    // bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut len = 2;
+    while len <= n {
+        let angle = sign * TAU / len as f32;
+
+        for start in (0..n).step_by(len) {
+            for k in 0..(len / 2) {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] += t_re;
+                im[a] += t_im;
+            }
+        }
+
+        len <<= 1;
+    }
+
+    if inverse {
+        for x in re.iter_mut().chain(im.iter_mut()) {
+            *x /= n as f32;
+        }
+    }
+}